@@ -1,164 +1,1472 @@
-use std::hash::{Hash, Hasher};
-use std::collections::hash_map::DefaultHasher;
+use secp256k1::{ecdsa::Signature, All, Message, PublicKey, Secp256k1, SecretKey};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Result, Formatter};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-/// Transaction item is used to represent a single transaction. Holds address to send the money to, address to send the money to, and the amount
-/// of money to send money to.
+/// Fixed-size digest produced by SHA-256. Used for block and transaction hashes so that every
+/// peer computing a hash over the same bytes arrives at the same, collision-resistant result.
+pub type Hash256 = [u8; 32];
+
+/// Renders a 32-byte digest as a lowercase hex string.
+fn hash_to_hex(hash : &Hash256) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Returns the current unix timestamp, in seconds.
+fn current_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before the unix epoch")
+        .as_secs()
+}
+
+/// Compact "bits" encoding of the easiest possible difficulty target, used for the genesis block.
+/// Follows bitcoin's nBits convention: the top byte is the number of significant bytes and the
+/// remaining three are the most significant mantissa bytes. Bitcoin mainnet's own genesis target
+/// (`0x1d00ffff`) requires the leading 4 bytes of the hash to be zero, which takes a tight
+/// single-threaded miner far too long to be practical here; this target is trivially satisfied
+/// (regtest-style) so mining stays fast enough to actually run the chain.
+const MAX_TARGET_BITS : u32 = 0x207fffff;
+
+/// Number of blocks between difficulty retargets.
+const RETARGET_INTERVAL : u64 = 10;
+
+/// Target number of seconds a single block should take to mine.
+const TARGET_BLOCK_TIME_SECS : u64 = 10;
+
+/// Expected number of seconds a full retarget interval should take.
+const TARGET_TIMESPAN_SECS : u64 = RETARGET_INTERVAL * TARGET_BLOCK_TIME_SECS;
+
+/// Fixed block-reward amount, in integer cents, minted by the coinbase output of each mined
+/// block, on top of any transaction fees collected.
+const COINBASE_REWARD : u64 = 5000;
+
+/// Decodes a compact "bits" value into a 256-bit big-endian target. `hash <= target` is the
+/// proof-of-work criterion.
+pub fn bits_to_target(bits : u32) -> Hash256 {
+    let exponent = (bits >> 24) as usize;
+    let mantissa = bits & 0x007fffff;
+    let mantissa_bytes = mantissa.to_be_bytes();
+    let mut target = [0u8; 32];
+
+    if exponent <= 3 {
+        let shift = 3 - exponent;
+        let value = mantissa >> (8 * shift);
+        target[29..32].copy_from_slice(&value.to_be_bytes()[1..]);
+    } else if exponent <= 32 {
+        let start = 32 - exponent;
+        target[start..start + 3].copy_from_slice(&mantissa_bytes[1..]);
+    }
+
+    target
+}
+
+/// Encodes a 256-bit big-endian target into compact "bits" form: the exponent is the number of
+/// significant bytes (counted from the first non-zero byte to the end) and the mantissa is the
+/// first three bytes of that range.
+pub fn target_to_bits(target : &Hash256) -> u32 {
+    let first_nonzero = match target.iter().position(|&b| b != 0) {
+        Some(i) => i,
+        None => return 0,
+    };
+
+    let mut size = (32 - first_nonzero) as u32;
+    let mut mantissa_bytes = [0u8; 3];
+
+    if size <= 3 {
+        let start = 32 - size as usize;
+        mantissa_bytes[(3 - size as usize)..].copy_from_slice(&target[start..32]);
+    } else {
+        mantissa_bytes.copy_from_slice(&target[first_nonzero..first_nonzero + 3]);
+    }
+
+    let mut mantissa = u32::from_be_bytes([0, mantissa_bytes[0], mantissa_bytes[1], mantissa_bytes[2]]);
+
+    // The mantissa's high bit doubles as a sign bit in the compact format; shift it out rather
+    // than let it be misread as negative.
+    if mantissa & 0x00800000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+
+    (size << 24) | mantissa
+}
+
+/// Rescales a compact target by `numerator`/`denominator`, renormalizing the mantissa and
+/// exponent together so the result stays in compact form. Used to retarget difficulty: `bits`
+/// carries only 24 bits of precision regardless of the full 256-bit target it represents, so the
+/// rescale is done directly on the compact mantissa rather than via full 256-bit arithmetic.
+fn scale_bits(bits : u32, numerator : u64, denominator : u64) -> u32 {
+    let mut exponent = (bits >> 24) as i64;
+    let mut mantissa = (bits & 0x00ffffff) as u64 * numerator / denominator;
+
+    // Keep the mantissa's high (sign) bit clear, as `target_to_bits` does.
+    while mantissa > 0x007fffff {
+        mantissa >>= 8;
+        exponent += 1;
+    }
+
+    // A target past 256 bits can't be represented; nothing is easier than the genesis target.
+    if exponent > 32 {
+        return MAX_TARGET_BITS;
+    }
+
+    while mantissa != 0 && mantissa < 0x00008000 && exponent > 3 {
+        mantissa <<= 8;
+        exponent -= 1;
+    }
+
+    let exponent = exponent.clamp(1, 32) as u32;
+
+    (exponent << 24) | (mantissa as u32 & 0x00ffffff)
+}
+
+/// Approximates the cumulative "work" a block mined at compact difficulty `bits` represents
+/// (mirroring bitcoin's `GetBlockProof`: roughly `2^256 / (target + 1)`), used to compare
+/// competing chains by total work rather than just block count. Since `bits` itself only carries
+/// 24 bits of precision, the target's most significant 16 bytes are enough to represent it
+/// exactly for any difficulty this chain is expected to reach, so the division is done in `u128`
+/// rather than with full 256-bit arithmetic.
+fn block_work(bits : u32) -> u128 {
+    let target = bits_to_target(bits);
+    let mut high_bytes = [0u8; 16];
+    high_bytes.copy_from_slice(&target[0..16]);
+    let approx_target = u128::from_be_bytes(high_bytes);
+
+    (u128::MAX / approx_target.saturating_add(1)).saturating_add(1)
+}
+
+/// A reference to one output of a previous transaction: the transaction's hash and the index of
+/// the output within it. The null OutPoint (zero hash, index `u32::MAX`) doesn't reference a real
+/// output; it marks the single input of a coinbase transaction.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    hash : Hash256,
+    index : u32
+}
+
+impl OutPoint {
+    /// Creates an OutPoint referencing the output at `index` of the transaction hashing to `hash`.
+    pub fn new(hash : Hash256, index : u32) -> Self {
+        OutPoint { hash : hash, index : index }
+    }
+
+    /// Returns the null OutPoint used by coinbase inputs.
+    pub fn null() -> Self {
+        OutPoint { hash : [0u8; 32], index : u32::MAX }
+    }
+
+    /// Returns whether this is the null OutPoint.
+    pub fn is_null(&self) -> bool {
+        *self == OutPoint::null()
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.hash);
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes
+    }
+}
+
+/// One input of a transaction: a reference to the previous output it spends.
+#[derive(Clone)]
+pub struct TransactionInput {
+    previous_output : OutPoint
+}
+
+impl TransactionInput {
+    /// Creates an input spending `previous_output`.
+    pub fn new(previous_output : OutPoint) -> Self {
+        TransactionInput { previous_output : previous_output }
+    }
+
+    /// Creates the single input of a coinbase transaction.
+    pub fn coinbase() -> Self {
+        TransactionInput { previous_output : OutPoint::null() }
+    }
+
+    /// Returns the output this input spends.
+    pub fn previous_output(&self) -> OutPoint {
+        self.previous_output
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.previous_output.to_bytes()
+    }
+}
+
+/// One output of a transaction: an amount, in integer cents, payable to a recipient address.
+/// Addresses are public keys: an output can only be spent by whoever signs with the matching
+/// private key.
+#[derive(Clone, Copy)]
+pub struct TransactionOutput {
+    value : u64,
+    recipient : PublicKey
+}
+
+impl TransactionOutput {
+    /// Creates an output paying `value` cents to `recipient`.
+    pub fn new(value : u64, recipient : PublicKey) -> Self {
+        TransactionOutput { value : value, recipient : recipient }
+    }
+
+    /// Returns the amount, in cents, this output pays.
+    pub fn value(&self) -> u64 {
+        self.value
+    }
+
+    /// Returns the address this output pays.
+    pub fn recipient(&self) -> &PublicKey {
+        &self.recipient
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.value.to_be_bytes());
+        bytes.extend_from_slice(&self.recipient.serialize());
+        bytes
+    }
+}
+
+/// Transaction item is used to represent a single transaction in the UTXO model: a list of inputs,
+/// each spending a previous output, and a list of outputs paying out new value. A coinbase
+/// transaction has no real input and mints the block reward instead.
+#[derive(Clone)]
 pub struct Transaction {
-    to : String,
-    from : String,
-    amount : f32
+    inputs : Vec<TransactionInput>,
+    outputs : Vec<TransactionOutput>
+}
+
+/// Resolves the output a `TransactionInput` spends. Implemented by `Blockchain` so transaction
+/// validation can look inputs up against the confirmed chain; a separate implementation also lets
+/// validation see the outputs produced by transactions already placed earlier in the block
+/// currently under construction.
+pub trait PreviousTransactionOutputProvider {
+    /// Returns the output referenced by `outpoint`, or `None` if it doesn't exist or was already spent.
+    fn previous_transaction_output(&self, outpoint : &OutPoint) -> Option<TransactionOutput>;
+}
+
+/// Resolves previous outputs against the transactions already placed earlier in a block under
+/// construction, falling back to `base` (typically the confirmed chain) for anything else.
+struct BlockTransactionOutputProvider<'a, P : PreviousTransactionOutputProvider> {
+    base : &'a P,
+    transactions : &'a [VerifiedTransaction]
+}
+
+/// Resolves previous outputs directly against an arbitrary UTXO map, rather than the live chain's
+/// own. Used to validate a side branch against a simulated UTXO set (the active set rolled back to
+/// the fork point) without mutating `Blockchain` itself.
+struct UtxoSetProvider<'a> {
+    utxos : &'a HashMap<OutPoint, TransactionOutput>
+}
+
+impl<'a> PreviousTransactionOutputProvider for UtxoSetProvider<'a> {
+    fn previous_transaction_output(&self, outpoint : &OutPoint) -> Option<TransactionOutput> {
+        self.utxos.get(outpoint).cloned()
+    }
+}
+
+impl<'a, P : PreviousTransactionOutputProvider> PreviousTransactionOutputProvider for BlockTransactionOutputProvider<'a, P> {
+    fn previous_transaction_output(&self, outpoint : &OutPoint) -> Option<TransactionOutput> {
+        for verified in self.transactions {
+            let transaction = verified.transaction();
+
+            if transaction.calculate_hash() == outpoint.hash {
+                return transaction.outputs.get(outpoint.index as usize).copied();
+            }
+        }
+
+        self.base.previous_transaction_output(outpoint)
+    }
+}
+
+/// A proof-of-stake seal attached to a header sealed under `ProofOfStakeConsensus`: the selected
+/// validator's identity and its signature over the header's content hash, attesting that this
+/// validator produced the block.
+struct StakeSeal {
+    validator : PublicKey,
+    signature : Signature
 }
 
-/// Block item is used to store a collection of transactions, the hash of the transactions, the hash of the last block in the chain, and the proof
-/// of work associated to the block.
+/// BlockHeader links a block to its predecessor and commits to its transactions, mirroring the
+/// header/body split used by most UTXO chains (see parity-zcash's `BlockHeader`). Hashing the
+/// header alone (rather than the whole block) is what the proof of work is computed over. The
+/// hashed fields below are the same under every consensus scheme; `stake_seal` is additional data
+/// attached only when the header is sealed under proof-of-stake, and isn't itself part of the hash.
+pub struct BlockHeader {
+    previous_header_hash : Hash256,
+    merkle_root_hash : Hash256,
+    time : u64,
+    bits : u32,
+    nonce : u64,
+    stake_seal : Option<StakeSeal>
+}
+
+/// Block item is used to store a header committing to the previous block and this block's
+/// transactions, the list of transactions themselves, and the resulting block hash.
 pub struct Block {
-    transactions : Vec<Transaction>,
-    hash : u64,
-    last_hash : u64,
-    proof : u64
+    header : BlockHeader,
+    transactions : Vec<VerifiedTransaction>,
+    hash : Hash256
 }
 
-/// Blockchain item is a public data structure that holds the blocks and the pending transactions that are yet to be added to the blockchain.
-pub struct Blockchain {
-    size : usize,
-    pending_transactions : Vec<Transaction>,
+/// A candidate chain of one or more blocks extending the active chain from an earlier block
+/// (keyed, in `Blockchain::side_branches`, by that block's hash) rather than from its current
+/// tip. Tracked alongside the active chain until its cumulative work overtakes it, at which point
+/// `Blockchain::maybe_reorganize` switches to it.
+struct SideBranch {
     blocks : Vec<Block>
 }
 
-impl Hash for Transaction {
-    /// Compute the hash of a transaction using the information included in the Transaction item.
-    /// The hash uses the dollars and cents of the amount since the f32 data type is not hashable.
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        let dollars : u32 = self.amount as u32;
-        let cents : u32 = ((self.amount - dollars as f32) * 100f32) as u32;
-
-        self.to.hash(state);
-        self.from.hash(state);
-        dollars.hash(state);
-        cents.hash(state);
+impl SideBranch {
+    /// Returns the total approximate proof-of-work performed by this branch's blocks.
+    fn work(&self) -> u128 {
+        self.blocks.iter().map(|block| block_work(block.header().bits())).sum()
     }
 }
 
-impl Hash for Block {
-    /// Compute the hash of a block using all the elements in each transaction.
-    fn hash<H : Hasher>(&self, state : &mut H) {
-        let mut dollars : u32;
-        let mut cents : u32;
+/// Blockchain item is a public data structure that holds the blocks and the pending transactions that are yet to be added to the blockchain.
+/// It also maintains the set of currently unspent transaction outputs (UTXOs) so incoming
+/// transactions can be validated against what's actually spendable.
+pub struct Blockchain {
+    size : usize,
+    pending_transactions : Vec<VerifiedTransaction>,
+    blocks : Vec<Block>,
+    utxos : HashMap<OutPoint, TransactionOutput>,
+    secp : Secp256k1<All>,
+    side_branches : HashMap<Hash256, SideBranch>,
+    consensus : Box<dyn Consensus>
+}
 
-        for transaction in &self.transactions {
-            dollars = transaction.amount as u32;
-            cents = ((transaction.amount - dollars as f32) * 100f32) as u32;
+/// Reasons a transaction can be rejected, either when it's submitted or when a block is assembled.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TransactionError {
+    /// The transaction's signature doesn't match its claimed signer.
+    InvalidSignature,
+    /// A non-coinbase input spends an output that doesn't exist or was already spent.
+    MissingOrSpentOutput,
+    /// A non-coinbase input spends an output that the transaction's signer doesn't own.
+    UnauthorizedSpend,
+    /// The transaction's outputs spend more value than its inputs provide.
+    OutputsExceedInputs,
+    /// A coinbase transaction was submitted directly. Only `Blockchain::add_block` may create one,
+    /// as the first transaction of a block it assembles.
+    UnexpectedCoinbase,
+    /// A block's transactions didn't start with exactly one coinbase transaction: either the
+    /// first transaction wasn't a coinbase, or a later one was.
+    MisplacedCoinbase,
+    /// A block's coinbase transaction minted more or less than the fixed block reward plus the
+    /// fees collected from its other transactions.
+    InvalidCoinbaseReward,
+    /// This node isn't entitled to seal a block right now under the active consensus scheme (e.g.
+    /// proof-of-stake didn't select it as the validator for this height).
+    ConsensusRejected
+}
 
-            transaction.to.hash(state);
-            transaction.from.hash(state);
-            dollars.hash(state);
-            cents.hash(state);
-        }
+/// Reasons a block or chain can fail validation.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    /// A block's header doesn't link to the hash of its predecessor.
+    BrokenChain,
+    /// A block's seal (its proof of work or, under proof-of-stake, its validator signature)
+    /// doesn't check out.
+    InvalidSeal,
+    /// A block's header's Merkle root doesn't match the one recomputed from its transactions.
+    MerkleRootMismatch,
+    /// A block's `bits` doesn't match the difficulty expected at its height under the retarget
+    /// schedule.
+    UnexpectedDifficulty,
+    /// One of a block's transactions failed UTXO validation: a double-spend, an unauthorized or
+    /// over-value spend, or a malformed coinbase.
+    InvalidTransaction(TransactionError)
+}
+
+impl PreviousTransactionOutputProvider for Blockchain {
+    fn previous_transaction_output(&self, outpoint : &OutPoint) -> Option<TransactionOutput> {
+        self.utxos.get(outpoint).cloned()
     }
 }
 
 impl Display for Transaction {
     fn fmt(&self, f: &mut Formatter) -> Result {
-        write!(f, "to: {}, from: {}, amount: {}", self.to, self.from, self.amount)
+        write!(f, "inputs: {}, outputs: {}", self.inputs.len(), self.outputs.len())
     }
 }
 
 impl Display for Block {
     fn fmt(&self, f : &mut Formatter) -> Result {
-        write!(f, "Hash: {}, Last Hash: {}, proof: {}, transactions size: {}", self.hash, self.last_hash, self.proof, self.transactions.len())
+        write!(
+            f,
+            "Hash: {}, Previous Header Hash: {}, Merkle Root: {}, Nonce: {}, transactions size: {}",
+            hash_to_hex(&self.hash),
+            hash_to_hex(&self.header.previous_header_hash),
+            hash_to_hex(&self.header.merkle_root_hash),
+            self.header.nonce,
+            self.transactions.len()
+        )
     }
 }
 
 impl Transaction {
-    /// Create and return a new Transaction item using a given 'to' address, 'from' address, and a monetary amount.
-    pub fn new(to : String, from : String, amount : f32) -> Self {
+    /// Create and return a new Transaction item from a given list of inputs and outputs.
+    pub fn new(inputs : Vec<TransactionInput>, outputs : Vec<TransactionOutput>) -> Self {
         Transaction {
-            to : to,
-            from : from,
-            amount : amount
+            inputs : inputs,
+            outputs : outputs
+        }
+    }
+
+    /// Creates the coinbase transaction every mined block places first: a single null input and
+    /// one output minting `reward` cents to `recipient`.
+    pub fn coinbase(recipient : PublicKey, reward : u64) -> Self {
+        Transaction {
+            inputs : vec![TransactionInput::coinbase()],
+            outputs : vec![TransactionOutput::new(reward, recipient)]
+        }
+    }
+
+    /// A transaction is a coinbase transaction when it has exactly one input and that input's
+    /// previous output is the null OutPoint.
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.len() == 1 && self.inputs[0].previous_output().is_null()
+    }
+
+    /// Returns this transaction's inputs.
+    pub fn inputs(&self) -> &[TransactionInput] {
+        &self.inputs
+    }
+
+    /// Returns this transaction's outputs.
+    pub fn outputs(&self) -> &[TransactionOutput] {
+        &self.outputs
+    }
+
+    /// Serializes the transaction's inputs followed by its outputs into a deterministic byte
+    /// buffer.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        for input in &self.inputs {
+            bytes.extend_from_slice(&input.to_bytes());
+        }
+
+        for output in &self.outputs {
+            bytes.extend_from_slice(&output.to_bytes());
+        }
+
+        bytes
+    }
+
+    /// Computes the SHA-256 hash of the transaction's serialized fields.
+    pub fn calculate_hash(&self) -> Hash256 {
+        let mut hasher = Sha256::new();
+        hasher.update(self.to_bytes());
+        hasher.finalize().into()
+    }
+
+    /// Signs this transaction's content hash with `secret_key`, pairing the resulting signature
+    /// with the signer's public key into an UnverifiedTransaction. The signature must still be
+    /// checked (see `UnverifiedTransaction::verify`) before the transaction can be trusted.
+    pub fn sign(self, secp : &Secp256k1<All>, secret_key : &SecretKey) -> UnverifiedTransaction {
+        let message = Message::from_digest(self.calculate_hash());
+        let signature = secp.sign_ecdsa(&message, secret_key);
+        let signer = PublicKey::from_secret_key(secp, secret_key);
+
+        UnverifiedTransaction {
+            transaction : self,
+            signature : signature,
+            signer : signer
         }
     }
+}
+
+/// Unauthenticated transaction content paired with a signature claiming to authorize it. Mirrors
+/// the split openethereum makes between unsigned transaction data and the signed-but-unchecked
+/// wrapper around it: an UnverifiedTransaction can never flow directly into block assembly, it
+/// must first be turned into a VerifiedTransaction via `verify`.
+pub struct UnverifiedTransaction {
+    transaction : Transaction,
+    signature : Signature,
+    signer : PublicKey
+}
+
+impl UnverifiedTransaction {
+    /// Checks the signature against the signer's public key and the transaction's content hash.
+    /// Returns the transaction wrapped as verified on success, or `TransactionError::InvalidSignature`
+    /// if the signature doesn't match.
+    pub fn verify(self, secp : &Secp256k1<All>) -> std::result::Result<VerifiedTransaction, TransactionError> {
+        let message = Message::from_digest(self.transaction.calculate_hash());
+
+        secp.verify_ecdsa(&message, &self.signature, &self.signer)
+            .map_err(|_| TransactionError::InvalidSignature)?;
+
+        Ok(VerifiedTransaction {
+            transaction : self.transaction,
+            signer : self.signer
+        })
+    }
+}
+
+/// A transaction whose signature has been checked against its signer's public key. Only
+/// VerifiedTransactions may be placed in `Blockchain::pending_transactions`.
+#[derive(Clone)]
+pub struct VerifiedTransaction {
+    transaction : Transaction,
+    signer : PublicKey
+}
+
+impl VerifiedTransaction {
+    /// Wraps `transaction` as verified without checking a signature, trusting `signer` as its
+    /// authorizer. Only used internally: for the coinbase transaction a block assembles for itself,
+    /// which has no signer to check against in the first place, and to requeue the transactions of
+    /// blocks orphaned by a reorg, which were already verified when they first entered a block.
+    fn trusted(transaction : Transaction, signer : PublicKey) -> Self {
+        VerifiedTransaction {
+            transaction : transaction,
+            signer : signer
+        }
+    }
+
+    /// Returns the verified transaction's content.
+    pub fn transaction(&self) -> &Transaction {
+        &self.transaction
+    }
+
+    /// Consumes the wrapper, returning the transaction content it verified.
+    pub fn into_transaction(self) -> Transaction {
+        self.transaction
+    }
+
+    /// Returns the public key whose signature authorized this transaction.
+    pub fn signer(&self) -> &PublicKey {
+        &self.signer
+    }
+}
+
+impl BlockHeader {
+    /// Creates a new BlockHeader linking to `previous_header_hash` and committing to
+    /// `merkle_root_hash`, stamped with `time`, targeting the difficulty encoded by `bits`, and
+    /// starting from `nonce`.
+    pub fn new(previous_header_hash : Hash256, merkle_root_hash : Hash256, time : u64, bits : u32, nonce : u64) -> Self {
+        BlockHeader {
+            previous_header_hash : previous_header_hash,
+            merkle_root_hash : merkle_root_hash,
+            time : time,
+            bits : bits,
+            nonce : nonce,
+            stake_seal : None
+        }
+    }
+
+    /// Returns the compact difficulty target this header was mined against.
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
 
-    /// static method that computes the hash of a Transaction item using the DefaultHasher collection object.
-    pub fn calculate_hash<T : Hash>(t : &T) -> u64 {
-        let mut s  = DefaultHasher::new();
-        t.hash(&mut s);
-        s.finish()
+    /// Returns the time this header was stamped with.
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    /// Returns the hash of the header this block builds on.
+    pub fn previous_header_hash(&self) -> Hash256 {
+        self.previous_header_hash
+    }
+
+    /// Returns the Merkle root this header commits to.
+    pub fn merkle_root_hash(&self) -> Hash256 {
+        self.merkle_root_hash
+    }
+
+    /// Attaches a proof-of-stake seal to this header. Doesn't affect `calculate_hash`, since the
+    /// seal attests to a hash that must already be fixed before it can be signed over.
+    fn set_stake_seal(&mut self, stake_seal : StakeSeal) {
+        self.stake_seal = Some(stake_seal);
+    }
+
+    /// Returns this header's proof-of-stake seal, if it was sealed under that scheme.
+    fn stake_seal(&self) -> Option<&StakeSeal> {
+        self.stake_seal.as_ref()
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        bytes.extend_from_slice(&self.previous_header_hash);
+        bytes.extend_from_slice(&self.merkle_root_hash);
+        bytes.extend_from_slice(&self.time.to_be_bytes());
+        bytes.extend_from_slice(&self.bits.to_be_bytes());
+        bytes.extend_from_slice(&self.nonce.to_be_bytes());
+
+        bytes
+    }
+
+    /// Computes the SHA-256 hash of the header's fields. This is the value a block's proof of
+    /// work is mined against.
+    pub fn calculate_hash(&self) -> Hash256 {
+        let mut hasher = Sha256::new();
+        hasher.update(self.to_bytes());
+        hasher.finalize().into()
     }
 }
 
 impl Block {
-    /// Creates and returns a new Block item using the given list of transactions and the last hash in the chain.
-    /// Upon being created, the proof of work is computed and the hash of the entire block is calculated.
-    /// NOTE: The computation of the proof of work may take a while (that's the whole point of the proof of work).
-    pub fn new(transactions : Vec<Transaction>, last_hash : u64) -> Self {
-        Block {
-            proof : Block::calculate_proof_of_work(last_hash),
-            hash : Block::calculate_hash(&transactions),
+    /// Creates and returns a new Block item using the given list of transactions and the previous
+    /// block's header hash. The transaction commitment is computed as a Merkle root, a header is
+    /// built referencing the previous block, and `consensus` seals it (mining a proof of work,
+    /// signing as a proof-of-stake validator, ...) before it's returned. `height` is this block's
+    /// position in the chain and is passed on to `consensus`; neither built-in scheme uses it
+    /// (proof-of-stake selects its validator from `previous_header_hash`). Fails if `consensus`
+    /// won't seal a block for this node (see `Consensus::seal`).
+    /// NOTE: Under proof-of-work, sealing may take a while (that's the whole point of mining).
+    pub fn new(
+        transactions : Vec<VerifiedTransaction>,
+        previous_header_hash : Hash256,
+        bits : u32,
+        height : u64,
+        consensus : &dyn Consensus
+    ) -> std::result::Result<Self, ValidationError> {
+        let merkle_root_hash = Block::calculate_merkle_root(&transactions);
+        let mut header = BlockHeader::new(previous_header_hash, merkle_root_hash, current_timestamp(), bits, 0);
+        let hash = consensus.seal(&mut header, height)?;
+
+        Ok(Block {
+            header : header,
             transactions : transactions,
-            last_hash : last_hash
+            hash : hash
+        })
+    }
+
+    /// Returns the hash stored for this block.
+    pub fn hash(&self) -> Hash256 {
+        self.hash
+    }
+
+    /// Returns this block's header.
+    pub fn header(&self) -> &BlockHeader {
+        &self.header
+    }
+
+    /// Returns this block's transactions.
+    pub fn transactions(&self) -> &[VerifiedTransaction] {
+        &self.transactions
+    }
+
+    /// Computes the Merkle root over a list of transactions: each transaction is hashed to a
+    /// leaf, then adjacent hashes are paired and hashed together to form the next level
+    /// (duplicating the last node when a level has an odd count) until a single root remains.
+    /// An empty transaction list yields the hash of an empty input.
+    fn calculate_merkle_root(transactions : &[VerifiedTransaction]) -> Hash256 {
+        if transactions.is_empty() {
+            let hasher = Sha256::new();
+            return hasher.finalize().into();
+        }
+
+        let mut level : Vec<Hash256> = transactions.iter().map(|t| t.transaction().calculate_hash()).collect();
+
+        while level.len() > 1 {
+            let mut next_level = Vec::with_capacity(level.len().div_ceil(2));
+
+            for pair in level.chunks(2) {
+                let mut hasher = Sha256::new();
+                hasher.update(pair[0]);
+                hasher.update(if pair.len() == 2 { pair[1] } else { pair[0] });
+                next_level.push(hasher.finalize().into());
+            }
+
+            level = next_level;
+        }
+
+        level[0]
+    }
+
+    /// Increments the header's nonce until its hash satisfies the proof-of-work criteria (the
+    /// hash, read as a big-endian 256-bit integer, must be less than or equal to the difficulty
+    /// target encoded by the header's `bits`), returning the resulting hash.
+    fn mine(header : &mut BlockHeader) -> Hash256 {
+        let target = bits_to_target(header.bits);
+        let mut hash = header.calculate_hash();
+
+        while !Block::is_proof_valid(&hash, &target) {
+            header.nonce += 1;
+            hash = header.calculate_hash();
+        }
+
+        hash
+    }
+
+    /// Determines if a mined hash meets the difficulty target, i.e. `hash <= target` when both
+    /// are read as big-endian 256-bit integers.
+    fn is_proof_valid(hash : &Hash256, target : &Hash256) -> bool {
+        hash <= target
+    }
+}
+
+/// A pluggable block-sealing scheme. `Blockchain` seals every block it produces, and checks every
+/// block it accepts, through a boxed `Consensus`, so the rest of the chain (UTXO validation,
+/// reorgs, ...) works the same whether blocks are proof-of-work mined or proof-of-stake signed.
+pub trait Consensus {
+    /// Seals `header`, which will sit at chain height `height`, stamping whatever proof this
+    /// scheme requires (a mined nonce, a validator's signature, ...) and returning the resulting
+    /// block hash. Fails if this node isn't entitled to seal a block at `height` under this
+    /// scheme.
+    fn seal(&self, header : &mut BlockHeader, height : u64) -> std::result::Result<Hash256, ValidationError>;
+
+    /// Checks that `header`, which hashes to `hash` and sits at chain height `height`, was
+    /// legitimately sealed under this scheme.
+    fn verify_seal(&self, header : &BlockHeader, hash : &Hash256, height : u64) -> bool;
+}
+
+/// The original difficulty-target mining scheme: a block is sealed by incrementing its header's
+/// nonce until the header's hash satisfies the difficulty target encoded in its `bits`.
+pub struct ProofOfWorkConsensus;
+
+impl Consensus for ProofOfWorkConsensus {
+    fn seal(&self, header : &mut BlockHeader, _height : u64) -> std::result::Result<Hash256, ValidationError> {
+        Ok(Block::mine(header))
+    }
+
+    fn verify_seal(&self, header : &BlockHeader, hash : &Hash256, _height : u64) -> bool {
+        Block::is_proof_valid(hash, &bits_to_target(header.bits()))
+    }
+}
+
+/// Splits a 64-bit seed into a different pseudo-random 64-bit value (the SplitMix64 mixing
+/// step). Used to turn a block hash into a deterministic, reproducible draw for
+/// proof-of-stake validator selection without pulling in a dedicated RNG crate.
+fn splitmix64(seed : u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// A proof-of-stake consensus scheme: blocks are sealed not by mining but by the signature of a
+/// validator deterministically selected, for each block, by weighted random draw over a registry
+/// of staked balances. `local_validator`/`local_secret_key` identify the validator identity this
+/// node seals blocks as, when it is the one selected.
+pub struct ProofOfStakeConsensus {
+    secp : Secp256k1<All>,
+    stakes : HashMap<PublicKey, u64>,
+    local_validator : PublicKey,
+    local_secret_key : SecretKey
+}
+
+impl ProofOfStakeConsensus {
+    /// Creates a proof-of-stake consensus scheme sealing blocks as `local_validator`, starting
+    /// from an empty stake registry.
+    pub fn new(secp : Secp256k1<All>, local_validator : PublicKey, local_secret_key : SecretKey) -> Self {
+        ProofOfStakeConsensus {
+            secp : secp,
+            stakes : HashMap::new(),
+            local_validator : local_validator,
+            local_secret_key : local_secret_key
         }
     }
 
-    pub fn calculate_hash<T : Hash>(t : &T) -> u64 {
-        let mut s = DefaultHasher::new();
-        t.hash(&mut s);
-        s.finish()
+    /// Sets `validator`'s staked balance, which weights how often it's selected to seal a block.
+    pub fn set_stake(&mut self, validator : PublicKey, stake : u64) {
+        self.stakes.insert(validator, stake);
     }
 
-    /// Determines if the proof proposed by the block is valid or not. The criteria is that the last 'N' digits of the proof + the last hash ends in
-    /// at least 6 '0' characters.
-    fn is_proof_valid(proof : u64) -> bool {
-        let proof_string = &proof.to_string();
+    /// Deterministically selects the validator entitled to seal the block following
+    /// `previous_header_hash`: seeds a PRNG from the hash, draws a point in `[0, total_stake)`,
+    /// and walks the stake registry (sorted by public key, for a reproducible order across nodes)
+    /// to find the validator whose cumulative-stake interval contains it. Returns `None` if no
+    /// stake has been registered.
+    fn select_validator(&self, previous_header_hash : &Hash256) -> Option<PublicKey> {
+        let total_stake : u64 = self.stakes.values().sum();
+
+        if total_stake == 0 {
+            return None;
+        }
 
-        for chr in proof_string.chars().rev().take(6) {
-            if chr != '0' {
-                return false;
+        let mut seed_bytes = [0u8; 8];
+        seed_bytes.copy_from_slice(&previous_header_hash[0..8]);
+        let draw = splitmix64(u64::from_be_bytes(seed_bytes)) % total_stake;
+
+        let mut registry : Vec<(&PublicKey, &u64)> = self.stakes.iter().collect();
+        registry.sort_by_key(|(validator, _)| validator.serialize());
+
+        let mut cumulative_stake = 0u64;
+
+        for (validator, stake) in registry {
+            cumulative_stake += stake;
+
+            if draw < cumulative_stake {
+                return Some(*validator);
             }
         }
 
-        true
+        None
     }
+}
+
+impl Consensus for ProofOfStakeConsensus {
+    fn seal(&self, header : &mut BlockHeader, _height : u64) -> std::result::Result<Hash256, ValidationError> {
+        let selected = self.select_validator(&header.previous_header_hash())
+            .filter(|validator| *validator == self.local_validator)
+            .ok_or(ValidationError::InvalidSeal)?;
 
-    /// Calculates and returns the hash of a String value using the DefaultHasher item.
-    fn calculate_string_hash(x : &String) -> u64 {
-        let mut hasher = DefaultHasher::new();
-        x.hash(&mut hasher);
-        hasher.finish()
+        let hash = header.calculate_hash();
+        let message = Message::from_digest(hash);
+        let signature = self.secp.sign_ecdsa(&message, &self.local_secret_key);
+
+        header.set_stake_seal(StakeSeal { validator : selected, signature : signature });
+
+        Ok(hash)
     }
 
-    /// Method used to find the proof of work. The proof starts at 0 and iteritively goes increments the value of the proof until the criteria is true.
-    fn calculate_proof_of_work(last_hash : u64) -> u64{
-        let mut proof : u64 = 0;
-        let mut string_proof : String = last_hash.to_string() + &proof.to_string();
-        let mut hash : u64 = Block::calculate_string_hash(&string_proof);
-        
-        while !Block::is_proof_valid(hash) {
-            proof += 1;
-            string_proof = last_hash.to_string() + &proof.to_string();
-            hash = Block::calculate_string_hash(&string_proof);
+    fn verify_seal(&self, header : &BlockHeader, hash : &Hash256, _height : u64) -> bool {
+        let seal = match header.stake_seal() {
+            Some(seal) => seal,
+            None => return false
+        };
+
+        let selected = match self.select_validator(&header.previous_header_hash()) {
+            Some(validator) => validator,
+            None => return false
+        };
+
+        if seal.validator != selected {
+            return false;
         }
 
-        hash
+        let message = Message::from_digest(*hash);
+        self.secp.verify_ecdsa(&message, &seal.signature, &seal.validator).is_ok()
+    }
+}
+
+impl Default for Blockchain {
+    fn default() -> Self {
+        Blockchain::new()
     }
 }
 
 impl Blockchain {
 
+    /// Creates an empty chain sealed under proof-of-work. Use `with_consensus` instead to run a
+    /// different scheme, e.g. proof-of-stake.
     pub fn new() -> Self {
-        Blockchain { size : 0, pending_transactions : Vec::<Transaction>::new(), blocks : Vec::<Block>::new() }
+        Blockchain::with_consensus(Box::new(ProofOfWorkConsensus))
+    }
+
+    /// Creates an empty chain sealed and verified under `consensus`.
+    pub fn with_consensus(consensus : Box<dyn Consensus>) -> Self {
+        Blockchain {
+            size : 0,
+            pending_transactions : Vec::<VerifiedTransaction>::new(),
+            blocks : Vec::<Block>::new(),
+            utxos : HashMap::new(),
+            secp : Secp256k1::new(),
+            side_branches : HashMap::new(),
+            consensus : consensus
+        }
     }
 
     pub fn get_size(&self) -> usize {
         self.size
     }
 
-    pub fn add_transaction(&mut self, transaction : Transaction) {
-        self.pending_transactions.push(transaction);
+    /// Verifies `transaction`'s signature and, on success, adds it to the pool of transactions
+    /// waiting to be mined. Rejects it with `TransactionError::InvalidSignature` if the signature
+    /// doesn't match, or `TransactionError::UnexpectedCoinbase` if it's a coinbase transaction
+    /// (those may only be created internally by `add_block`, never submitted).
+    pub fn add_transaction(&mut self, transaction : UnverifiedTransaction) -> std::result::Result<(), TransactionError> {
+        let verified = transaction.verify(&self.secp)?;
+
+        if verified.transaction().is_coinbase() {
+            return Err(TransactionError::UnexpectedCoinbase);
+        }
+
+        self.pending_transactions.push(verified);
+        Ok(())
+    }
+
+    /// Assembles the pending transactions into a new block referencing the current tip (or the
+    /// zero hash if this is the genesis block), seals it under the active consensus scheme, and
+    /// appends it to the chain. A coinbase transaction paying `miner` the fixed block reward plus
+    /// any collected fees is placed first. The block is rejected, leaving the chain (and the
+    /// pending pool) unchanged, if any pending transaction spends an output that is missing,
+    /// already spent (including by another pending transaction spending the same output), or
+    /// insufficient to cover its outputs, or if this node isn't entitled to seal a block right now.
+    pub fn add_block(&mut self, miner : PublicKey) -> std::result::Result<(), TransactionError> {
+        let previous_header_hash = self.blocks.last().map(|block| block.hash()).unwrap_or([0u8; 32]);
+        let bits = self.next_bits();
+
+        let mut fees : u64 = 0;
+        let mut spent_outpoints : HashSet<OutPoint> = HashSet::new();
+
+        for (index, transaction) in self.pending_transactions.iter().enumerate() {
+            if transaction.transaction().is_coinbase() {
+                continue;
+            }
+
+            for input in transaction.transaction().inputs() {
+                if !spent_outpoints.insert(input.previous_output()) {
+                    return Err(TransactionError::MissingOrSpentOutput);
+                }
+            }
+
+            let provider = BlockTransactionOutputProvider { base : &*self, transactions : &self.pending_transactions[..index] };
+            fees += Blockchain::validate_transaction(transaction, &provider)?;
+        }
+
+        let mut block_transactions = Vec::with_capacity(self.pending_transactions.len() + 1);
+        block_transactions.push(VerifiedTransaction::trusted(Transaction::coinbase(miner, COINBASE_REWARD + fees), miner));
+        block_transactions.extend(self.pending_transactions.iter().cloned());
+
+        let height = self.blocks.len() as u64;
+        let block = Block::new(block_transactions, previous_header_hash, bits, height, self.consensus.as_ref())
+            .map_err(|_| TransactionError::ConsensusRejected)?;
+
+        // Only now that the block has been fully assembled and sealed do we remove its
+        // transactions from the pending pool, so a rejected block leaves it untouched.
+        self.pending_transactions.clear();
+        self.apply_utxo_updates(&block);
+        self.blocks.push(block);
+        self.size += 1;
+
+        Ok(())
+    }
+
+    /// Checks that every input of `transaction` spends an output `provider` can resolve that's
+    /// owned by the transaction's signer, and that the total input value covers the total output
+    /// value, returning the difference as the fee.
+    fn validate_transaction<P : PreviousTransactionOutputProvider>(
+        transaction : &VerifiedTransaction,
+        provider : &P
+    ) -> std::result::Result<u64, TransactionError> {
+        let mut input_value : u64 = 0;
+
+        for input in transaction.transaction().inputs() {
+            let output = provider
+                .previous_transaction_output(&input.previous_output())
+                .ok_or(TransactionError::MissingOrSpentOutput)?;
+
+            if output.recipient() != transaction.signer() {
+                return Err(TransactionError::UnauthorizedSpend);
+            }
+
+            input_value += output.value();
+        }
+
+        let output_value : u64 = transaction.transaction().outputs().iter().map(|output| output.value()).sum();
+
+        if input_value < output_value {
+            return Err(TransactionError::OutputsExceedInputs);
+        }
+
+        Ok(input_value - output_value)
+    }
+
+    /// Removes the outputs `block`'s transactions spend from the UTXO set and inserts the ones
+    /// they create.
+    fn apply_utxo_updates(&mut self, block : &Block) {
+        Blockchain::apply_utxo_updates_to(&mut self.utxos, block);
+    }
+
+    /// Same as `apply_utxo_updates`, but against an arbitrary UTXO map rather than `self.utxos`, so
+    /// a candidate side branch can be simulated without mutating the chain.
+    fn apply_utxo_updates_to(utxos : &mut HashMap<OutPoint, TransactionOutput>, block : &Block) {
+        for verified in block.transactions() {
+            let transaction = verified.transaction();
+
+            if !transaction.is_coinbase() {
+                for input in transaction.inputs() {
+                    utxos.remove(&input.previous_output());
+                }
+            }
+
+            let hash = transaction.calculate_hash();
+
+            for (index, output) in transaction.outputs().iter().enumerate() {
+                utxos.insert(OutPoint::new(hash, index as u32), *output);
+            }
+        }
+    }
+
+    /// Checks that `block`'s transactions would be valid if applied on top of `utxos`: every
+    /// non-coinbase transaction's inputs are unspent within the block and elsewhere valid (see
+    /// `validate_transaction`), exactly the first transaction is a coinbase, and that coinbase
+    /// mints no more and no less than the fixed block reward plus the fees collected from the
+    /// other transactions. Applies the block's effects to `utxos` on success, leaving it untouched
+    /// on failure.
+    fn validate_block_against_utxos(
+        block : &Block,
+        utxos : &mut HashMap<OutPoint, TransactionOutput>
+    ) -> std::result::Result<(), TransactionError> {
+        let mut fees : u64 = 0;
+        let mut spent_outpoints : HashSet<OutPoint> = HashSet::new();
+
+        for (index, verified) in block.transactions().iter().enumerate() {
+            if verified.transaction().is_coinbase() {
+                if index != 0 {
+                    return Err(TransactionError::MisplacedCoinbase);
+                }
+
+                continue;
+            }
+
+            for input in verified.transaction().inputs() {
+                if !spent_outpoints.insert(input.previous_output()) {
+                    return Err(TransactionError::MissingOrSpentOutput);
+                }
+            }
+
+            let provider = BlockTransactionOutputProvider {
+                base : &UtxoSetProvider { utxos : utxos },
+                transactions : &block.transactions()[..index]
+            };
+
+            fees += Blockchain::validate_transaction(verified, &provider)?;
+        }
+
+        match block.transactions().first() {
+            Some(coinbase) if coinbase.transaction().is_coinbase() => {
+                let minted : u64 = coinbase.transaction().outputs().iter().map(|output| output.value()).sum();
+
+                if minted != COINBASE_REWARD + fees {
+                    return Err(TransactionError::InvalidCoinbaseReward);
+                }
+            }
+            _ => return Err(TransactionError::MisplacedCoinbase)
+        }
+
+        Blockchain::apply_utxo_updates_to(utxos, block);
+
+        Ok(())
+    }
+
+    /// Reverses `apply_utxo_updates` for `block`: removes the outputs it created and restores the
+    /// ones its non-coinbase inputs spent. Restoring a spent output requires recovering its
+    /// original content, since it was deleted from the UTXO set when the block was first applied;
+    /// `find_output` locates it by searching the still-active chain and the orphaned blocks
+    /// preceding `block` for the transaction that produced it.
+    fn rollback_utxo_updates(&mut self, block : &Block, orphaned_blocks : &[Block]) {
+        Blockchain::rollback_utxo_updates_to(&mut self.utxos, block, orphaned_blocks, &self.blocks);
+    }
+
+    /// Same as `rollback_utxo_updates`, but against an arbitrary UTXO map and an explicitly passed
+    /// `chain` (the still-active portion of the confirmed chain), so a candidate side branch's
+    /// fork point can be simulated without mutating the chain.
+    fn rollback_utxo_updates_to(
+        utxos : &mut HashMap<OutPoint, TransactionOutput>,
+        block : &Block,
+        orphaned_blocks : &[Block],
+        chain : &[Block]
+    ) {
+        for verified in block.transactions() {
+            let transaction = verified.transaction();
+            let hash = transaction.calculate_hash();
+
+            for index in 0..transaction.outputs().len() {
+                utxos.remove(&OutPoint::new(hash, index as u32));
+            }
+
+            if !transaction.is_coinbase() {
+                for input in transaction.inputs() {
+                    let outpoint = input.previous_output();
+
+                    if let Some(output) = Blockchain::find_output(&outpoint, chain, orphaned_blocks) {
+                        utxos.insert(outpoint, output);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Searches `chain` (the still-active portion of the confirmed chain) and `orphaned_blocks`
+    /// (the orphaned blocks preceding the one being rolled back, which the reverse-order rollback
+    /// has not reached yet) for the transaction that produced
+    /// `outpoint`, returning the output it created.
+    fn find_output(outpoint : &OutPoint, chain : &[Block], orphaned_blocks : &[Block]) -> Option<TransactionOutput> {
+        for block in chain.iter().chain(orphaned_blocks.iter()) {
+            for verified in block.transactions() {
+                let transaction = verified.transaction();
+
+                if transaction.calculate_hash() == outpoint.hash {
+                    return transaction.outputs().get(outpoint.index as usize).copied();
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Computes the difficulty "bits" the next block should be mined at. Every
+    /// `RETARGET_INTERVAL` blocks, the actual time the last interval took is compared against the
+    /// expected time and the previous target is rescaled (clamped to a factor of 4 in either
+    /// direction); otherwise the difficulty carries over unchanged.
+    fn next_bits(&self) -> u32 {
+        Blockchain::expected_bits(&self.blocks)
+    }
+
+    /// Computes the difficulty "bits" the block following `chain` should be mined at (see
+    /// `next_bits`). Takes the preceding chain explicitly, rather than reading `self.blocks`
+    /// directly, so `validate` can also check historical blocks' difficulty as it replays them.
+    fn expected_bits(chain : &[Block]) -> u32 {
+        let height = chain.len() as u64;
+
+        if height == 0 || !height.is_multiple_of(RETARGET_INTERVAL) {
+            return chain.last().map(|block| block.header().bits()).unwrap_or(MAX_TARGET_BITS);
+        }
+
+        let first = &chain[(height - RETARGET_INTERVAL) as usize];
+        let last = &chain[(height - 1) as usize];
+        let actual_timespan = last.header().time().saturating_sub(first.header().time()).max(1);
+        let clamped_timespan = actual_timespan.clamp(TARGET_TIMESPAN_SECS / 4, TARGET_TIMESPAN_SECS * 4);
+
+        scale_bits(last.header().bits(), clamped_timespan, TARGET_TIMESPAN_SECS)
+    }
+
+    /// Checks the parts of a block's shape that don't depend on its position in a chain: that its
+    /// seal (proof of work, or a proof-of-stake validator signature) checks out for chain height
+    /// `height`, and that its header's Merkle root matches the one recomputed from its
+    /// transactions. Shared by both `validate` (checking the active chain) and `accept_block`
+    /// (checking a freshly submitted block).
+    fn validate_block_shape(&self, block : &Block, height : u64) -> std::result::Result<(), ValidationError> {
+        if !self.consensus.verify_seal(block.header(), &block.hash(), height) {
+            return Err(ValidationError::InvalidSeal);
+        }
+
+        if Block::calculate_merkle_root(block.transactions()) != block.header().merkle_root_hash() {
+            return Err(ValidationError::MerkleRootMismatch);
+        }
+
+        Ok(())
+    }
+
+    /// Walks the active chain from genesis, checking that each block's header links to the
+    /// previous block's hash (the zero hash for genesis), that its seal is valid, and that its
+    /// Merkle root matches its transactions.
+    pub fn validate(&self) -> std::result::Result<(), ValidationError> {
+        let mut previous_hash = [0u8; 32];
+        let mut utxos : HashMap<OutPoint, TransactionOutput> = HashMap::new();
+
+        for (height, block) in self.blocks.iter().enumerate() {
+            if block.header().previous_header_hash() != previous_hash {
+                return Err(ValidationError::BrokenChain);
+            }
+
+            self.validate_block_shape(block, height as u64)?;
+
+            if block.header().bits() != Blockchain::expected_bits(&self.blocks[..height]) {
+                return Err(ValidationError::UnexpectedDifficulty);
+            }
+
+            Blockchain::validate_block_against_utxos(block, &mut utxos).map_err(ValidationError::InvalidTransaction)?;
+            previous_hash = block.hash();
+        }
+
+        Ok(())
+    }
+
+    /// Submits a candidate block received from elsewhere. Blocks extending the active chain's tip
+    /// are appended directly, after checking that they mine at the expected difficulty and that
+    /// their transactions validate against the current UTXO set; blocks extending any earlier
+    /// point are filed into a side branch, which is then reorganized onto if it has overtaken the
+    /// active chain's total work.
+    pub fn accept_block(&mut self, block : Block) -> std::result::Result<(), ValidationError> {
+        self.validate_block_shape(&block, self.blocks.len() as u64)?;
+
+        let tip_hash = self.blocks.last().map(|b| b.hash()).unwrap_or([0u8; 32]);
+
+        if block.header().previous_header_hash() == tip_hash {
+            if block.header().bits() != self.next_bits() {
+                return Err(ValidationError::UnexpectedDifficulty);
+            }
+
+            Blockchain::validate_block_against_utxos(&block, &mut self.utxos).map_err(ValidationError::InvalidTransaction)?;
+            self.blocks.push(block);
+            self.size += 1;
+        } else {
+            self.file_side_branch(block);
+        }
+
+        self.maybe_reorganize();
+
+        Ok(())
+    }
+
+    /// Appends `block` to the side branch it extends. If `block`'s predecessor is the current tip
+    /// of an existing side branch, it's chained onto that branch (so branches more than one block
+    /// deep are assembled correctly); otherwise it starts a new branch forking from its
+    /// predecessor.
+    fn file_side_branch(&mut self, block : Block) {
+        let previous_hash = block.header().previous_header_hash();
+
+        for branch in self.side_branches.values_mut() {
+            if branch.blocks.last().map(|tip| tip.hash()) == Some(previous_hash) {
+                branch.blocks.push(block);
+                return;
+            }
+        }
+
+        self.side_branches
+            .entry(previous_hash)
+            .or_insert_with(|| SideBranch { blocks : Vec::new() })
+            .blocks
+            .push(block);
+    }
+
+    /// Returns the index into `self.blocks` of the block hashing to `fork_hash`, or `None` if
+    /// `fork_hash` is the zero hash (forking from before genesis) or doesn't match any block.
+    fn fork_height_index(&self, fork_hash : &Hash256) -> Option<usize> {
+        self.blocks.iter().position(|block| block.hash() == *fork_hash)
+    }
+
+    /// Switches the active chain to any side branch whose total work exceeds the active chain's work
+    /// from the fork point onward. Rolls back the orphaned blocks (returning their non-coinbase
+    /// transactions to `pending_transactions`) and applies the side branch's blocks in their
+    /// place, re-deriving the UTXO set across the switch.
+    fn maybe_reorganize(&mut self) {
+        let candidates : Vec<Hash256> = self.side_branches.keys().copied().collect();
+
+        for fork_hash in candidates {
+            // Index of the first block after the fork point, i.e. the first one to be orphaned.
+            let fork_index = match self.fork_height_index(&fork_hash) {
+                Some(index) => index + 1,
+                None if fork_hash == [0u8; 32] => {
+                    // Forks from before genesis: treat the fork point as preceding the whole chain.
+                    0
+                }
+                None => continue
+            };
+
+            let active_work : u128 = self.blocks[fork_index..]
+                .iter()
+                .map(|block| block_work(block.header().bits()))
+                .sum();
+
+            let branch_work = self.side_branches[&fork_hash].work();
+
+            if branch_work > active_work {
+                self.reorganize_onto(fork_hash, fork_index);
+            }
+        }
+    }
+
+    /// Re-derives the UTXO set as of `fork_index` (the active chain rolled back to the fork point)
+    /// and checks that every one of `branch`'s blocks would still validate against it in turn,
+    /// carrying each block's effects forward so later blocks see earlier ones' outputs. Returns
+    /// `Err` without touching `self` if any block's transactions don't validate.
+    fn validate_branch(
+        &self,
+        branch : &SideBranch,
+        fork_index : usize,
+        orphaned_blocks : &[Block]
+    ) -> std::result::Result<(), TransactionError> {
+        let mut utxos = self.utxos.clone();
+
+        for (index, block) in orphaned_blocks.iter().enumerate().rev() {
+            Blockchain::rollback_utxo_updates_to(&mut utxos, block, &orphaned_blocks[..index], &self.blocks[..fork_index]);
+        }
+
+        for block in &branch.blocks {
+            Blockchain::validate_block_against_utxos(block, &mut utxos)?;
+        }
+
+        Ok(())
+    }
+
+    /// Performs the reorg onto the side branch forking from `fork_hash` at `fork_index`: splits
+    /// off and rolls back the blocks after the fork point, replaces them with the side branch's
+    /// blocks, and requeues the orphaned blocks' non-coinbase transactions for re-mining. Aborts,
+    /// discarding the side branch and leaving the active chain, UTXO set and pending pool
+    /// unchanged, if the branch doesn't actually validate against the UTXO set
+    /// rebuilt at the fork point (e.g. it contains a double-spend that only becomes visible once
+    /// simulated against the chain it would replace).
+    fn reorganize_onto(&mut self, fork_hash : Hash256, fork_index : usize) {
+        let branch = match self.side_branches.get(&fork_hash) {
+            Some(branch) => branch,
+            None => return
+        };
+
+        let orphaned_blocks_preview = &self.blocks[fork_index..];
+
+        if self.validate_branch(branch, fork_index, orphaned_blocks_preview).is_err() {
+            self.side_branches.remove(&fork_hash);
+            return;
+        }
+
+        let branch = match self.side_branches.remove(&fork_hash) {
+            Some(branch) => branch,
+            None => return
+        };
+
+        let orphaned_blocks = self.blocks.split_off(fork_index);
+
+        for (index, block) in orphaned_blocks.iter().enumerate().rev() {
+            self.rollback_utxo_updates(block, &orphaned_blocks[..index]);
+        }
+
+        for block in &orphaned_blocks {
+            for verified in block.transactions() {
+                if !verified.transaction().is_coinbase() {
+                    self.pending_transactions.push(VerifiedTransaction::trusted(
+                        verified.transaction().clone(),
+                        *verified.signer()
+                    ));
+                }
+            }
+        }
+
+        for block in branch.blocks {
+            self.apply_utxo_updates(&block);
+            self.blocks.push(block);
+        }
+
+        self.size = self.blocks.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_pair(seed : u8) -> (SecretKey, PublicKey) {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[seed; 32]).expect("valid secret key seed");
+        let public_key = PublicKey::from_secret_key(&secp, &secret_key);
+        (secret_key, public_key)
+    }
+
+    #[test]
+    fn bits_to_target_and_back_round_trips() {
+        for bits in [MAX_TARGET_BITS, 0x1d00ffff, 0x1b0404cb, 0x207fffff, 0x03123456, 0x04123456] {
+            let target = bits_to_target(bits);
+            assert_eq!(target_to_bits(&target), bits, "bits {:#010x} didn't round-trip", bits);
+        }
+    }
+
+    #[test]
+    fn add_block_rejects_a_double_spend_and_leaves_the_pending_pool_untouched() {
+        let (alice_secret, alice_public) = key_pair(1);
+        let (_, bob_public) = key_pair(2);
+        let (_, miner_public) = key_pair(3);
+
+        let mut chain = Blockchain::new();
+        chain.add_block(alice_public).expect("genesis block mints to alice");
+
+        let coinbase_hash = chain.blocks[0].transactions()[0].transaction().calculate_hash();
+        let outpoint = OutPoint::new(coinbase_hash, 0);
+
+        let secp = Secp256k1::new();
+        let spend = |value : u64| {
+            Transaction::new(
+                vec![TransactionInput::new(outpoint)],
+                vec![TransactionOutput::new(value, bob_public)]
+            ).sign(&secp, &alice_secret)
+        };
+
+        chain.add_transaction(spend(1000)).expect("first spend is well-formed");
+        chain.add_transaction(spend(2000)).expect("second spend is well-formed on its own");
+
+        let result = chain.add_block(miner_public);
+
+        assert_eq!(result, Err(TransactionError::MissingOrSpentOutput));
+        assert_eq!(chain.pending_transactions.len(), 2, "a rejected block must leave the pending pool unchanged");
+    }
+
+    #[test]
+    fn a_longer_side_branch_reorganizes_onto_the_active_chain() {
+        let (_, active_miner) = key_pair(4);
+        let (_, branch_miner) = key_pair(5);
+
+        let mut chain = Blockchain::new();
+        chain.add_block(active_miner).expect("active chain's only block mines");
+
+        let branch_block_1 = Block::new(
+            vec![VerifiedTransaction::trusted(Transaction::coinbase(branch_miner, COINBASE_REWARD), branch_miner)],
+            [0u8; 32],
+            MAX_TARGET_BITS,
+            0,
+            &ProofOfWorkConsensus
+        ).expect("branch's first block seals");
+
+        let branch_block_2 = Block::new(
+            vec![VerifiedTransaction::trusted(Transaction::coinbase(branch_miner, COINBASE_REWARD), branch_miner)],
+            branch_block_1.hash(),
+            MAX_TARGET_BITS,
+            1,
+            &ProofOfWorkConsensus
+        ).expect("branch's second block seals");
+
+        let branch_block_1_hash = branch_block_1.hash();
+        let branch_block_2_hash = branch_block_2.hash();
+
+        chain.accept_block(branch_block_1).expect("branch's first block has a valid shape");
+        chain.accept_block(branch_block_2).expect("branch's second block has a valid shape");
+
+        assert_eq!(chain.blocks.len(), 2, "the two-block side branch should have overtaken the one-block active chain");
+        assert_eq!(chain.blocks[0].hash(), branch_block_1_hash);
+        assert_eq!(chain.blocks[1].hash(), branch_block_2_hash);
     }
 
-    pub fn add_block(&mut self) {
-        let last_hash : u64 = Block::calculate_hash(self.blocks.last().unwrap());
+    #[test]
+    fn accept_block_rejects_a_tip_extending_double_spend() {
+        let (_, alice_public) = key_pair(6);
+        let (_, bob_public) = key_pair(7);
+        let (_, miner_public) = key_pair(8);
+
+        let mut chain = Blockchain::new();
+        chain.add_block(alice_public).expect("genesis block mints to alice");
+
+        let coinbase_hash = chain.blocks[0].transactions()[0].transaction().calculate_hash();
+        let outpoint = OutPoint::new(coinbase_hash, 0);
+
+        let spend = |value : u64| {
+            VerifiedTransaction::trusted(
+                Transaction::new(
+                    vec![TransactionInput::new(outpoint)],
+                    vec![TransactionOutput::new(value, bob_public)]
+                ),
+                alice_public
+            )
+        };
+
+        let double_spending_block = Block::new(
+            vec![
+                VerifiedTransaction::trusted(Transaction::coinbase(miner_public, COINBASE_REWARD), miner_public),
+                spend(1000),
+                spend(2000)
+            ],
+            chain.blocks[0].hash(),
+            MAX_TARGET_BITS,
+            1,
+            &ProofOfWorkConsensus
+        ).expect("double-spending block still seals, since PoW doesn't check transactions");
+
+        let result = chain.accept_block(double_spending_block);
+
+        assert_eq!(result, Err(ValidationError::InvalidTransaction(TransactionError::MissingOrSpentOutput)));
+        assert_eq!(chain.blocks.len(), 1, "a rejected block must leave the active chain unchanged");
+    }
+
+    #[test]
+    fn accept_block_rejects_an_unexpected_difficulty() {
+        let (_, miner_public) = key_pair(9);
+
+        let mut chain = Blockchain::new();
+        chain.add_block(miner_public).expect("genesis block mines at the genesis difficulty");
+
+        let wrong_bits = 0x1f7fffff;
+        assert_ne!(wrong_bits, chain.next_bits(), "test needs a bits value the chain doesn't actually expect next");
+
+        let off_difficulty_block = Block::new(
+            vec![VerifiedTransaction::trusted(Transaction::coinbase(miner_public, COINBASE_REWARD), miner_public)],
+            chain.blocks[0].hash(),
+            wrong_bits,
+            1,
+            &ProofOfWorkConsensus
+        ).expect("block still seals at a different, still-trivial difficulty");
+
+        let result = chain.accept_block(off_difficulty_block);
+
+        assert_eq!(result, Err(ValidationError::UnexpectedDifficulty));
+        assert_eq!(chain.blocks.len(), 1, "a rejected block must leave the active chain unchanged");
     }
 }